@@ -2,11 +2,13 @@
 #![windows_subsystem = "console"]
 
 use tauri::Emitter;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use futures::StreamExt;
 use dirs;
 use std::path::Path;
 use tauri_plugin_opener::OpenerExt;
+use fs4::{available_space, FileExt};
+use sha2::{Digest, Sha256};
 
 // Logging commands that can be called from the frontend
 #[tauri::command]
@@ -44,25 +46,41 @@ fn open_file_in_folder(path: String, app: tauri::AppHandle) -> Result<(), String
 mod download_tracker {
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
-    use tokio::sync::oneshot;
-    
+    use tokio::sync::{oneshot, Semaphore};
+
     // Struct to hold information about an active download
     pub struct ActiveDownload {
         pub cancel_tx: oneshot::Sender<()>,
     }
-    
-    // Global map to track active downloads by ID
+
+    // A single entry in a batch enqueued via `enqueue_downloads`
+    #[derive(Clone, serde::Deserialize)]
+    pub struct DownloadJob {
+        pub url: String,
+        pub save_path: String,
+        pub auth_token: Option<String>,
+        pub download_id: String,
+        pub expected_sha256: Option<String>,
+    }
+
+    // How many downloads are allowed to run at once when enqueued as a batch
+    const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+    // Global map to track active downloads by ID, plus the shared semaphore that bounds
+    // how many queued downloads run concurrently.
     lazy_static::lazy_static! {
-        static ref ACTIVE_DOWNLOADS: Arc<Mutex<HashMap<String, ActiveDownload>>> = 
+        static ref ACTIVE_DOWNLOADS: Arc<Mutex<HashMap<String, ActiveDownload>>> =
             Arc::new(Mutex::new(HashMap::new()));
+        static ref DOWNLOAD_SEMAPHORE: Mutex<Arc<Semaphore>> =
+            Mutex::new(Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENCY)));
     }
-    
+
     // Add a new active download
     pub fn add_download(download_id: String, cancel_tx: oneshot::Sender<()>) {
         let mut downloads = ACTIVE_DOWNLOADS.lock().unwrap();
         downloads.insert(download_id, ActiveDownload { cancel_tx });
     }
-    
+
     // Cancel a download by ID
     pub fn cancel_download(download_id: &str) -> bool {
         let mut downloads = ACTIVE_DOWNLOADS.lock().unwrap();
@@ -74,135 +92,367 @@ mod download_tracker {
             false
         }
     }
-    
+
     // Remove a download from tracking (when it completes or fails)
     pub fn remove_download(download_id: &str) {
         let mut downloads = ACTIVE_DOWNLOADS.lock().unwrap();
         downloads.remove(download_id);
     }
+
+    // Grab a handle to the current download semaphore so a queued job can acquire a permit
+    // before it starts. Cloning the Arc (rather than borrowing) lets `set_max_concurrency`
+    // swap in a fresh semaphore without disturbing permits already handed out.
+    pub fn semaphore() -> Arc<Semaphore> {
+        DOWNLOAD_SEMAPHORE.lock().unwrap().clone()
+    }
+
+    // Replace the shared semaphore with one sized for the new concurrency limit
+    pub fn set_max_concurrency(max: usize) {
+        let mut semaphore = DOWNLOAD_SEMAPHORE.lock().unwrap();
+        *semaphore = Arc::new(Semaphore::new(max.max(1)));
+    }
 }
 
-// Download command that downloads a file from a URL and saves it to a specified path
-#[tauri::command]
-async fn download_file(url: &str, save_path: &str, auth_token: Option<String>, download_id: String, app_handle: tauri::AppHandle) -> Result<(), String> {
-    log::info!("Starting download from {} to {} with ID {}", url, save_path, download_id);
-    
-    // Create a channel for cancellation
-    let (cancel_tx, mut cancel_rx) = tokio::sync::oneshot::channel::<()>();
-    
-    // Register this download for cancellation tracking
-    download_tracker::add_download(download_id.clone(), cancel_tx);
-    
-    // Parse URL to check if we need to add auth token
-    let parsed_url = url::Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
-    let final_url = url.to_string();
-    let mut headers = reqwest::header::HeaderMap::new();
-    
-    // Add auth token to headers if provided, regardless of hostname
-    if let Some(token) = auth_token {
-        log::info!("Adding auth token from parameters");
-        headers.insert("X-Auth-Token", token.parse().map_err(|_| "Invalid auth token")?);
+// Outcome of a single HEAD-less attempt at streaming the file to disk. `Cancelled` and
+// `Fatal` abort the download outright; `Transient` is the only variant the retry loop
+// in `download_file` will act on.
+enum DownloadAttemptError {
+    Cancelled,
+    Transient(String),
+    Fatal(String),
+    InsufficientSpace { required: u64, available: u64 },
+    ChecksumMismatch { expected: String, actual: String },
+}
+
+impl std::fmt::Display for DownloadAttemptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadAttemptError::Cancelled => write!(f, "Download cancelled"),
+            DownloadAttemptError::Transient(message) | DownloadAttemptError::Fatal(message) => write!(f, "{}", message),
+            DownloadAttemptError::InsufficientSpace { required, available } => write!(
+                f,
+                "Not enough free space to download: {} bytes required, only {} bytes available",
+                required, available
+            ),
+            DownloadAttemptError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {}, got {}",
+                expected, actual
+            ),
+        }
     }
-    // Fallback to query parameter if no auth_token parameter provided
-    else if let Some(auth_token) = parsed_url.query_pairs().find(|(key, _)| key == "auth_token").map(|(_, value)| value.to_string()) {
-        log::info!("Adding auth token from URL query parameter");
-        headers.insert("X-Auth-Token", auth_token.parse().map_err(|_| "Invalid auth token")?);
+}
+
+// Persist the real number of downloaded bytes next to the `.tmp` file, so a resume after
+// the *process* restarts (crash, kill, fresh invocation for the same `download_id`) can
+// tell how much real data is on disk. The `.tmp` file's own length can't be trusted for
+// this once preallocation has run: `allocate` extends it straight to `total_size` before
+// a single body byte arrives, so a restart right after that point would otherwise read
+// back as "fully downloaded" and resume from a zero-filled/garbage offset.
+async fn write_progress_sidecar(progress_path: &str, downloaded: u64) {
+    if let Err(e) = tokio::fs::write(progress_path, downloaded.to_string()).await {
+        log::warn!("Failed to persist download progress to {}: {}", progress_path, e);
     }
-    
-    // Create HTTP client with headers
-    let client = reqwest::Client::builder()
-        .default_headers(headers.clone())
-        .build()
-        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
-    
-    // Send HEAD request to get file size first
-    let head_response = client.head(&final_url).headers(headers.clone()).send().await
-        .map_err(|e| format!("Failed to send HEAD request: {}", e))?;
-    
-    // Check if HEAD request was successful
-    if !head_response.status().is_success() {
-        log::warn!("HEAD request failed with status: {}, proceeding with GET request", head_response.status());
+}
+
+// Drop the sidecar once it's no longer needed: the download finished, or the file it was
+// tracking is about to be recreated from scratch.
+async fn remove_progress_sidecar(progress_path: &str) {
+    let _ = tokio::fs::remove_file(progress_path).await;
+}
+
+// Parse the start offset out of a `Content-Range: bytes <start>-<end>/<total>` response
+// header, returning None for anything that doesn't look like that (other units, a `*`
+// placeholder range, missing header, ...).
+fn parse_content_range_start(value: &str) -> Option<u64> {
+    value.strip_prefix("bytes ")?.split('-').next()?.trim().parse::<u64>().ok()
+}
+
+// Decide where a (re)started attempt should resume from, given what's genuinely on disk.
+// Pulled out as a pure function (rather than left inline in `download_file_with_cancel`)
+// so the sidecar-vs-file-length trust rules - the subtlest part of resuming after a
+// process restart - can be unit tested without standing up real file I/O.
+fn resolve_resume_offset(file_len: Option<u64>, sidecar_progress: Option<u64>, total_size: u64) -> u64 {
+    match file_len {
+        // No partial file on disk at all, so there's nothing to resume - ignore any
+        // sidecar left behind by something else (it can't refer to this attempt's file).
+        None => 0,
+        Some(file_len) => match sidecar_progress {
+            // Never trust the sidecar past what's actually on disk: if the last write
+            // never made it past the OS before a hard kill, the sidecar can report more
+            // bytes than exist, and seeking past real EOF would zero-fill the gap instead
+            // of erroring out.
+            Some(offset) => offset.min(file_len),
+            None => {
+                if total_size > 0 && file_len >= total_size {
+                    // A preallocated-but-unconfirmed file with no progress record: the
+                    // process died before any bytes were confirmed written, so restart
+                    // rather than resume from a zero-filled/garbage offset.
+                    0
+                } else {
+                    file_len
+                }
+            }
+        },
     }
-    
-    // Get total size if available from HEAD request
-    let mut total_size = head_response.content_length().unwrap_or(0);
-    log::info!("File size from HEAD request: {} bytes", total_size);
-    
-    // If we didn't get size from HEAD, send GET request to get it
-    let (response, needs_get_request) = if total_size == 0 {
-        log::info!("No content length from HEAD, sending GET request to determine size");
-        let get_response = client.get(&final_url).headers(headers.clone()).send().await
-            .map_err(|e| format!("Failed to send GET request: {}", e))?;
-            
-        if !get_response.status().is_success() {
-            return Err(format!("HTTP request failed with status: {}", get_response.status()));
-        }
-        
-        total_size = get_response.content_length().unwrap_or(0);
-        log::info!("File size from GET request: {} bytes", total_size);
-        (get_response, false) // We already have the response
+}
+
+// Verify the streamed bytes against the expected checksum, returning the actual digest on
+// mismatch for the caller to report. Pulled out as a pure function, hasher state aside, so
+// the comparison itself can be unit tested independently of a real download.
+fn verify_checksum(expected: &str, hasher: &mut Sha256) -> Result<(), String> {
+    let actual = format!("{:x}", hasher.finalize_reset());
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
     } else {
-        // Send GET request for actual download
-        let get_response = client.get(&final_url).headers(headers.clone()).send().await
-            .map_err(|e| format!("Failed to send GET request: {}", e))?;
-            
-        if !get_response.status().is_success() {
-            return Err(format!("HTTP request failed with status: {}", get_response.status()));
+        Err(actual)
+    }
+}
+
+// How many recent interval samples feed the rolling-average throughput, smoothing out a
+// single noisy 500ms window without lagging behind real speed changes too much.
+const SPEED_SAMPLE_WINDOW: usize = 5;
+
+// Richer replacement for the old single-number "speed" field: an instantaneous rolling
+// average alongside the whole download's overall average (carried across retries), so the UI can show a steady readout
+// even on bursty connections.
+#[derive(serde::Serialize)]
+struct DownloadProgressRecord {
+    percentage: u64,
+    downloaded: u64,
+    total: u64,
+    elapsed_secs: f64,
+    last_throughput: u64,
+    avg_throughput: u64,
+    eta: Option<u64>,
+}
+
+// Perform one GET + streaming-read attempt, resuming from whatever is already on disk at
+// `tmp_path`. On success the file is flushed and atomically renamed to `resolved_path`.
+// Broken out of `download_file_with_cancel` so the retry loop can re-run just this part on
+// transient failures without re-registering the download or re-resolving paths.
+async fn attempt_download(
+    client: &reqwest::Client,
+    final_url: &str,
+    headers: &reqwest::header::HeaderMap,
+    tmp_path: &str,
+    resolved_path: &str,
+    total_size: &mut u64,
+    resume_offset_ref: &mut u64,
+    preallocated: &mut bool,
+    cancel_rx: &mut tokio::sync::oneshot::Receiver<()>,
+    app_handle: &tauri::AppHandle,
+    download_id: &str,
+    last_progress: &mut u64,
+    expected_sha256: Option<&str>,
+    mut hasher: Option<&mut Sha256>,
+    hasher_primed: &mut bool,
+    session_start: &mut Option<(std::time::Instant, u64)>,
+    speed_samples: &mut std::collections::VecDeque<u64>,
+) -> Result<u64, DownloadAttemptError> {
+    // `resume_offset` is tracked by the caller across attempts rather than re-read from
+    // the file's size here: once the file has been preallocated below, its on-disk size
+    // jumps straight to `total_size`, so re-probing it on every retry would make this
+    // attempt think the whole file is already downloaded.
+    let mut resume_offset = *resume_offset_ref;
+    if resume_offset > 0 {
+        log::info!("Found partial download of {} bytes, attempting to resume", resume_offset);
+    }
+    let progress_path = format!("{}.progress", tmp_path);
+
+    // Build the range header for a resume attempt, if any.
+    let mut get_headers = headers.clone();
+    if resume_offset > 0 {
+        get_headers.insert(
+            reqwest::header::RANGE,
+            format!("bytes={}-", resume_offset).parse().map_err(|_| DownloadAttemptError::Fatal("Invalid range header".to_string()))?,
+        );
+    }
+
+    // Check for cancellation before spending time on a request that might not be needed
+    if matches!(cancel_rx.try_recv(), Ok(())) {
+        return Err(DownloadAttemptError::Cancelled);
+    }
+
+    // Send GET request for the actual download
+    let response = client.get(final_url).headers(get_headers).send().await
+        .map_err(|e| DownloadAttemptError::Transient(format!("Failed to send GET request: {}", e)))?;
+
+    let status = response.status();
+    if status.is_client_error() {
+        // 4xx (auth, not-found, ...) is never worth retrying
+        return Err(DownloadAttemptError::Fatal(format!("HTTP request failed with status: {}", status)));
+    }
+    if !status.is_success() && status != reqwest::StatusCode::PARTIAL_CONTENT {
+        // 5xx and anything else unexpected might clear up on its own
+        return Err(DownloadAttemptError::Transient(format!("HTTP request failed with status: {}", status)));
+    }
+
+    // If we asked for a range but the server ignored it and sent 200 instead of 206,
+    // it's returning the whole file again, so we have to truncate and start over.
+    let mut resumed = resume_offset > 0 && status == reqwest::StatusCode::PARTIAL_CONTENT;
+
+    // A 206 alone isn't proof the server actually resumed from where we asked - some
+    // proxies/CDNs rewrite the requested range - so confirm it against Content-Range
+    // before trusting that the upcoming bytes belong at `resume_offset`. Anything else
+    // (mismatched start, missing/unparseable header) falls back to the same "restart from
+    // scratch" path as a server that ignored the Range request outright, rather than
+    // writing bytes at a silently wrong file offset.
+    if resumed {
+        match response.headers().get(reqwest::header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_content_range_start)
+        {
+            Some(reported_start) if reported_start == resume_offset => {}
+            Some(reported_start) => {
+                log::warn!(
+                    "Server's Content-Range starts at {} instead of the requested {}, restarting download from scratch",
+                    reported_start, resume_offset
+                );
+                resumed = false;
+            }
+            None => {
+                log::warn!("206 response had no usable Content-Range header, restarting download from scratch to stay safe");
+                resumed = false;
+            }
         }
-        (get_response, true) // We need to use this response
-    };
-    
-    // If we didn't already get the response from the GET request above, send another GET request
-    let response = if needs_get_request {
-        response
-    } else {
-        // Send GET request for actual download
-        client.get(&final_url).headers(headers.clone()).send().await
-            .map_err(|e| format!("Failed to send GET request: {}", e))?
-    };
-    
-    // Get total size if available (in case it changed)
-    total_size = response.content_length().unwrap_or(total_size);
+    }
+
+    if resume_offset > 0 && !resumed {
+        log::warn!("Server did not resume from the requested offset, restarting download from scratch");
+        resume_offset = 0;
+        // The file is about to be recreated from scratch, so any previous preallocation
+        // no longer applies to it.
+        *preallocated = false;
+        remove_progress_sidecar(&progress_path).await;
+        // The download is effectively starting over from byte 0, so the throughput
+        // baseline it was averaging against no longer applies either.
+        *session_start = None;
+        speed_samples.clear();
+    }
+    *resume_offset_ref = resume_offset;
+
+    // Keep the checksum hasher consistent with whatever ends up on disk: if we're
+    // resuming bytes the hasher hasn't seen yet (a fresh process picking up a partial
+    // file left by a previous run), feed them in once; if we're starting over, reset it.
+    if let Some(h) = hasher.as_deref_mut() {
+        if resumed {
+            if !*hasher_primed {
+                // Stream the existing partial file through the hasher in fixed-size chunks
+                // rather than reading it whole - these are large Telegram files, and a
+                // multi-gigabyte `Vec<u8>` on resume would risk the very OOM this feature
+                // is meant to make downloads safer against. Read only up to `resume_offset`:
+                // once the file has been preallocated, its on-disk length reaches all the
+                // way to `total_size`, well past the real data actually downloaded so far.
+                let mut existing_file = tokio::fs::File::open(tmp_path).await
+                    .map_err(|e| DownloadAttemptError::Fatal(format!("Failed to open partial file for checksum resume: {}", e)))?;
+                let mut buf = vec![0u8; 64 * 1024];
+                let mut remaining = resume_offset;
+                while remaining > 0 {
+                    let want = remaining.min(buf.len() as u64) as usize;
+                    let read = existing_file.read(&mut buf[..want]).await
+                        .map_err(|e| DownloadAttemptError::Fatal(format!("Failed to read partial file for checksum resume: {}", e)))?;
+                    if read == 0 {
+                        break;
+                    }
+                    h.update(&buf[..read]);
+                    remaining -= read as u64;
+                }
+                *hasher_primed = true;
+            }
+        } else {
+            *h = Sha256::new();
+            *hasher_primed = true;
+        }
+    }
+
+    // If HEAD didn't give us a size, fall back to what the GET response reports
+    // (adjusting for any bytes we're resuming from, since 206 only counts what's left).
+    if *total_size == 0 {
+        *total_size = response.content_length().unwrap_or(0) + resume_offset;
+    }
     log::info!("Final file size: {} bytes", total_size);
-    
-    // Handle relative paths by resolving them against the user's download directory
-    let resolved_path = if Path::new(&save_path).is_absolute() {
-        save_path.to_string()
-    } else {
-        // For relative paths, resolve against the user's download directory
-        let download_dir = dirs::download_dir().ok_or("Could not determine download directory")?;
-        let full_path = download_dir.join(save_path);
-        // Create parent directories if they don't exist
-        if let Some(parent) = full_path.parent() {
-            tokio::fs::create_dir_all(parent).await.map_err(|e| format!("Failed to create directories: {}", e))?;
+
+    // Fail fast rather than leaving a half-written multi-gigabyte file behind when the
+    // target volume doesn't have room for what's left to download. Only needed the first
+    // time we size this tmp file: once `allocate` below has reserved the blocks, a retry
+    // re-demanding the same space would spuriously see it as "unavailable" (it now belongs
+    // to this file) and abort a transfer that's already fully provisioned.
+    let remaining_to_write = total_size.saturating_sub(resume_offset);
+    if remaining_to_write > 0 && !*preallocated {
+        let volume = Path::new(tmp_path).parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        let volume = volume.to_path_buf();
+        // `available_space` is a blocking syscall, same as `allocate` below - a slow or
+        // network-mounted download directory can stall it just as badly, so it needs the
+        // same spawn_blocking treatment to avoid parking the async reactor thread.
+        let space_check = tokio::task::spawn_blocking(move || available_space(&volume)).await
+            .map_err(|e| DownloadAttemptError::Fatal(format!("Free space check task panicked: {}", e)))?;
+        match space_check {
+            Ok(available) if available < remaining_to_write => {
+                return Err(DownloadAttemptError::InsufficientSpace { required: remaining_to_write, available });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::warn!("Could not check free space on {:?}, proceeding anyway: {}", tmp_path, e);
+            }
         }
-        full_path.to_string_lossy().to_string()
+    }
+
+    // Open the working file for writing (never in append mode): `allocate` below moves the
+    // file's logical end-of-file out to `total_size` up front, and an O_APPEND file always
+    // writes at the *current* end-of-file, which would land continuation bytes at the wrong
+    // offset and leave a zero-filled gap behind. Explicit seeking below keeps the write
+    // position correct regardless of where preallocation has extended the file to.
+    let mut file = if resumed {
+        tokio::fs::OpenOptions::new().write(true).open(tmp_path).await
+            .map_err(|e| DownloadAttemptError::Fatal(format!("Failed to open partial file for resume: {}", e)))?
+    } else {
+        let created = tokio::fs::File::create(tmp_path).await.map_err(|e| DownloadAttemptError::Fatal(format!("Failed to create file: {}", e)))?;
+        // Creating truncates the file, so any preallocation from an earlier attempt is gone
+        *preallocated = false;
+        created
     };
-    
-    log::info!("Resolved download path: {}", resolved_path);
-    
-    // Create file to save to
-    let mut file = tokio::fs::File::create(&resolved_path).await.map_err(|e| format!("Failed to create file: {}", e))?;
-    
+
+    // Preallocate the file to its final size so the filesystem reserves real disk blocks
+    // up front, not just a sparse logical length (`set_len` alone doesn't stop a
+    // concurrent writer from eating the space the free-space check above just confirmed
+    // was free). Only do this once per tmp file: `allocate` already reserved everything up
+    // to `total_size` on the first attempt, so later retries have nothing left to add.
+    if *total_size > 0 && !*preallocated {
+        let size = *total_size;
+        let std_file = file.try_clone().await
+            .map_err(|e| DownloadAttemptError::Fatal(format!("Failed to prepare file for preallocation: {}", e)))?
+            .into_std().await;
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = std_file.allocate(size) {
+                log::warn!("Failed to preallocate file to {} bytes, continuing without it: {}", size, e);
+            }
+        }).await.map_err(|e| DownloadAttemptError::Fatal(format!("Preallocation task panicked: {}", e)))?;
+        *preallocated = true;
+    }
+
+    // Writes must start at the real resume point, not wherever preallocation left the file
+    // cursor (file was just opened/created, so this is a no-op seek to 0 in the fresh case).
+    file.seek(std::io::SeekFrom::Start(resume_offset)).await
+        .map_err(|e| DownloadAttemptError::Fatal(format!("Failed to seek to resume offset: {}", e)))?;
+
     // Stream the response and write to file
     let mut stream = response.bytes_stream();
-    let mut downloaded: u64 = 0;
-    let mut last_progress: u64 = 0;
-    
-    // Track timing for speed calculation
-    let start_time = std::time::Instant::now();
-    let mut last_update_time = start_time;
-    let mut last_downloaded = 0u64;
-    
+    let mut downloaded: u64 = resume_offset;
+
+    // Track timing for speed calculation. `start_time`/`download_start_offset` mark when
+    // and where *this whole download* began, not just this attempt: they're carried in by
+    // the caller across retries (same pattern as `resume_offset_ref`/`preallocated`) so a
+    // retry doesn't reset the averaging window back to a single noisy sample.
+    let (start_time, download_start_offset) = *session_start.get_or_insert_with(|| (std::time::Instant::now(), resume_offset));
+    let mut last_update_time = std::time::Instant::now();
+    let mut last_downloaded = resume_offset;
+
     // Main download loop
     loop {
         // Check for cancellation
         match cancel_rx.try_recv() {
-            Ok(()) => {
-                log::info!("Download {} cancelled", download_id);
-                download_tracker::remove_download(&download_id);
-                return Err("Download cancelled".to_string());
-            }
+            Ok(()) => return Err(DownloadAttemptError::Cancelled),
             Err(tokio::sync::oneshot::error::TryRecvError::Closed) => {
                 // Channel closed, but no cancellation signal received
                 // Continue with download
@@ -211,7 +461,7 @@ async fn download_file(url: &str, save_path: &str, auth_token: Option<String>, d
                 // No message yet, continue with download
             }
         }
-        
+
         // Try to read next chunk with timeout to allow for cancellation checks
         tokio::select! {
             // Try to read the next chunk
@@ -219,58 +469,85 @@ async fn download_file(url: &str, save_path: &str, auth_token: Option<String>, d
                 match result {
                     Some(Ok(chunk)) => {
                         // Write chunk to file
-                        file.write_all(&chunk).await.map_err(|e| format!("Failed to write to file: {}", e))?;
+                        file.write_all(&chunk).await.map_err(|e| DownloadAttemptError::Fatal(format!("Failed to write to file: {}", e)))?;
+                        if let Some(h) = hasher.as_deref_mut() {
+                            h.update(&chunk);
+                        }
                         downloaded += chunk.len() as u64;
-                        
+
                         // Calculate speed and ETA periodically (every 500ms)
                         let now = std::time::Instant::now();
                         if now.duration_since(last_update_time).as_millis() >= 500 {
                             let time_elapsed = now.duration_since(last_update_time).as_secs_f64();
                             let bytes_since_last = downloaded - last_downloaded;
-                            
+
                             if time_elapsed > 0.0 {
-                                let speed_bps = (bytes_since_last as f64 / time_elapsed) as u64; // bytes per second
-                                
-                                // Calculate ETA if we know total size
-                                let eta_seconds = if total_size > 0 && speed_bps > 0 {
-                                    let remaining = total_size - downloaded;
-                                    Some(remaining / speed_bps)
+                                let interval_speed = (bytes_since_last as f64 / time_elapsed) as u64;
+
+                                // Roll this interval into the window and average it, so one
+                                // slow or bursty sample doesn't make the readout jump around
+                                speed_samples.push_back(interval_speed);
+                                if speed_samples.len() > SPEED_SAMPLE_WINDOW {
+                                    speed_samples.pop_front();
+                                }
+                                let last_throughput = speed_samples.iter().sum::<u64>() / speed_samples.len() as u64;
+
+                                // Average throughput over the whole download so far (across
+                                // any retries), which is steadier still and what the ETA
+                                // below is based on
+                                let elapsed_secs = now.duration_since(start_time).as_secs_f64();
+                                let bytes_this_download = downloaded - download_start_offset;
+                                let avg_throughput = if elapsed_secs > 0.0 {
+                                    (bytes_this_download as f64 / elapsed_secs) as u64
+                                } else {
+                                    0
+                                };
+
+                                let eta = if *total_size > 0 && avg_throughput > 0 {
+                                    Some(total_size.saturating_sub(downloaded) / avg_throughput)
                                 } else {
                                     None
                                 };
-                                
-                                // Emit detailed progress event with speed and ETA
-                                let progress_data = serde_json::json!({
-                                    "percentage": if total_size > 0 { (downloaded as f64 / total_size as f64 * 100.0) as u64 } else { 0 },
-                                    "downloaded": downloaded,
-                                    "total": total_size,
-                                    "speed": speed_bps,
-                                    "eta": eta_seconds
-                                });
-                                
-                                app_handle.emit("download_progress_detailed", progress_data)
-                                    .map_err(|e| format!("Failed to emit detailed progress: {}", e))?;
+
+                                let progress_record = DownloadProgressRecord {
+                                    percentage: if *total_size > 0 { (downloaded as f64 / *total_size as f64 * 100.0) as u64 } else { 0 },
+                                    downloaded,
+                                    total: *total_size,
+                                    elapsed_secs,
+                                    last_throughput,
+                                    avg_throughput,
+                                    eta,
+                                };
+
+                                let _ = app_handle.emit("download_progress_detailed", &progress_record);
                             }
-                            
+
                             last_update_time = now;
                             last_downloaded = downloaded;
+                            // Piggyback the sidecar write on the same 500ms cadence as the
+                            // detailed progress event rather than on every chunk, so a
+                            // multi-gigabyte transfer isn't doing a disk write per network read.
+                            write_progress_sidecar(&progress_path, downloaded).await;
                         }
-                        
+
                         // Emit regular progress event more frequently for better UX
-                        if total_size > 0 {
-                            let progress = (downloaded as f64 / total_size as f64 * 100.0) as u64;
-                            
+                        if *total_size > 0 {
+                            let progress = (downloaded as f64 / *total_size as f64 * 100.0) as u64;
+
                             // Only emit progress updates at 1% intervals to reduce event overhead
-                            if progress >= last_progress + 1 || progress == 100 {
-                                app_handle.emit("download_progress", progress).map_err(|e| format!("Failed to emit progress: {}", e))?;
-                                last_progress = progress;
+                            if progress >= *last_progress + 1 || progress == 100 {
+                                let _ = app_handle.emit("download_progress", progress);
+                                *last_progress = progress;
                             }
                         }
                     }
                     Some(Err(e)) => {
-                        // Error reading chunk
-                        download_tracker::remove_download(&download_id);
-                        return Err(format!("Failed to read chunk: {}", e));
+                        // Truncated/reset stream mid-transfer is exactly what resume exists for.
+                        // Persist immediately rather than waiting for the next 500ms tick, since
+                        // this is exactly the moment a restart needs an accurate offset for.
+                        *resume_offset_ref = downloaded;
+                        write_progress_sidecar(&progress_path, downloaded).await;
+                        return Err(DownloadAttemptError::Transient(format!("Failed to read chunk: {}", e)));
                     }
                     None => {
                         // Download completed
@@ -284,12 +561,224 @@ async fn download_file(url: &str, save_path: &str, auth_token: Option<String>, d
             }
         }
     }
-    
-    // Remove download from tracking
-    download_tracker::remove_download(&download_id);
-    
-    log::info!("Download {} completed successfully", download_id);
-    Ok(())
+
+    // Flush the working file and atomically finalize it to the requested name, so a
+    // reader never observes a partially-written file at `resolved_path`.
+    file.flush().await.map_err(|e| DownloadAttemptError::Fatal(format!("Failed to flush file: {}", e)))?;
+    drop(file);
+
+    // Verify integrity before publishing the file under its final name
+    if let (Some(expected), Some(h)) = (expected_sha256, hasher.as_deref_mut()) {
+        if let Err(actual) = verify_checksum(expected, h) {
+            let _ = tokio::fs::remove_file(tmp_path).await;
+            remove_progress_sidecar(&progress_path).await;
+            return Err(DownloadAttemptError::ChecksumMismatch { expected: expected.to_string(), actual });
+        }
+        log::info!("Checksum verified for download {}", download_id);
+    }
+
+    tokio::fs::rename(tmp_path, resolved_path).await
+        .map_err(|e| DownloadAttemptError::Fatal(format!("Failed to finalize downloaded file: {}", e)))?;
+    remove_progress_sidecar(&progress_path).await;
+
+    if expected_sha256.is_some() {
+        let _ = app_handle.emit("download_verified", serde_json::json!({ "download_id": download_id }));
+    }
+
+    *resume_offset_ref = downloaded;
+    Ok(downloaded)
+}
+
+// How long to wait before the first retry, the cap that backoff doubles up to, and the
+// total time budget across all attempts before a transient failure is given up on.
+const RETRY_INITIAL_DELAY_MS: u64 = 500;
+const RETRY_MAX_DELAY_MS: u64 = 30_000;
+const RETRY_MAX_ELAPSED_SECS: u64 = 600;
+
+// Download command that downloads a file from a URL and saves it to a specified path
+#[tauri::command]
+async fn download_file(url: &str, save_path: &str, auth_token: Option<String>, download_id: String, app_handle: tauri::AppHandle, expected_sha256: Option<String>) -> Result<(), String> {
+    // Create and register the cancellation channel for this download, then hand off to the
+    // shared implementation. `enqueue_downloads` registers its own channel up front (so a
+    // queued job stays cancellable while waiting for a concurrency slot) and calls
+    // `download_file_with_cancel` directly instead, to avoid a second, overwriting
+    // registration racing with the first.
+    let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel::<()>();
+    download_tracker::add_download(download_id.clone(), cancel_tx);
+    download_file_with_cancel(url, save_path, auth_token, download_id, app_handle, expected_sha256, cancel_rx).await
+}
+
+// Shared download implementation, parameterized over an already-registered cancellation
+// receiver so callers that need to register it before this function runs (`enqueue_downloads`,
+// while still waiting on a concurrency permit) can hand in the exact same receiver instead of
+// a fresh one getting registered over theirs.
+async fn download_file_with_cancel(url: &str, save_path: &str, auth_token: Option<String>, download_id: String, app_handle: tauri::AppHandle, expected_sha256: Option<String>, mut cancel_rx: tokio::sync::oneshot::Receiver<()>) -> Result<(), String> {
+    log::info!("Starting download from {} to {} with ID {}", url, save_path, download_id);
+
+    // Parse URL to check if we need to add auth token
+    let parsed_url = url::Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
+    let final_url = url.to_string();
+    let mut headers = reqwest::header::HeaderMap::new();
+
+    // Add auth token to headers if provided, regardless of hostname
+    if let Some(token) = auth_token {
+        log::info!("Adding auth token from parameters");
+        headers.insert("X-Auth-Token", token.parse().map_err(|_| "Invalid auth token")?);
+    }
+    // Fallback to query parameter if no auth_token parameter provided
+    else if let Some(auth_token) = parsed_url.query_pairs().find(|(key, _)| key == "auth_token").map(|(_, value)| value.to_string()) {
+        log::info!("Adding auth token from URL query parameter");
+        headers.insert("X-Auth-Token", auth_token.parse().map_err(|_| "Invalid auth token")?);
+    }
+
+    // Create HTTP client with headers
+    let client = reqwest::Client::builder()
+        .default_headers(headers.clone())
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+
+    // Send HEAD request to get file size first
+    let head_response = client.head(&final_url).headers(headers.clone()).send().await
+        .map_err(|e| format!("Failed to send HEAD request: {}", e))?;
+
+    // Check if HEAD request was successful
+    if !head_response.status().is_success() {
+        log::warn!("HEAD request failed with status: {}, proceeding with GET request", head_response.status());
+    }
+
+    // Get total size if available from HEAD request. This is the authoritative size used
+    // for progress/ETA math, since a resumed GET only reports the *remaining* bytes.
+    let mut total_size = head_response.content_length().unwrap_or(0);
+    log::info!("File size from HEAD request: {} bytes", total_size);
+
+    // Handle relative paths by resolving them against the user's download directory
+    let resolved_path = if Path::new(&save_path).is_absolute() {
+        save_path.to_string()
+    } else {
+        // For relative paths, resolve against the user's download directory
+        let download_dir = dirs::download_dir().ok_or("Could not determine download directory")?;
+        let full_path = download_dir.join(save_path);
+        // Create parent directories if they don't exist
+        if let Some(parent) = full_path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| format!("Failed to create directories: {}", e))?;
+        }
+        full_path.to_string_lossy().to_string()
+    };
+    let tmp_path = format!("{}.tmp", resolved_path);
+
+    log::info!("Resolved download path: {} (working file: {})", resolved_path, tmp_path);
+
+    // Retry the GET + streaming attempt with exponential backoff on transient failures.
+    // Each retry resumes from whatever made it to disk on the previous attempt.
+    //
+    // `resume_offset` is probed from disk once, here - not re-probed inside each attempt -
+    // since once the file has been preallocated its on-disk length reaches all the way to
+    // `total_size`, which would no longer reflect how much real data has actually landed.
+    //
+    // That also means the `.tmp` file's length can't be trusted as the *initial* offset
+    // either: if the process itself was killed after preallocation but before the body
+    // finished streaming, the file on disk is already `total_size` bytes long even though
+    // none (or few) of them are real. The progress sidecar written by `attempt_download`
+    // tracks genuinely-downloaded bytes across process restarts, so prefer it; only fall
+    // back to the file's length when there's no sidecar to read, and even then refuse to
+    // trust a length that already reached `total_size` (that can only be preallocation,
+    // never a real completed download - a finished download gets renamed away immediately).
+    let progress_path = format!("{}.progress", tmp_path);
+    let file_len = tokio::fs::metadata(&tmp_path).await.ok().map(|m| m.len());
+    let sidecar_progress = tokio::fs::read_to_string(&progress_path).await.ok()
+        .and_then(|s| s.trim().parse::<u64>().ok());
+    if let (Some(file_len), None) = (file_len, sidecar_progress) {
+        if total_size > 0 && file_len >= total_size {
+            log::warn!(
+                "Found a {}-byte .tmp file with no progress record; treating it as \
+                 an unconfirmed preallocation from a prior run and restarting from scratch",
+                file_len
+            );
+        }
+    }
+    let mut resume_offset = resolve_resume_offset(file_len, sidecar_progress, total_size);
+    let retry_start = std::time::Instant::now();
+    let mut attempt: u32 = 0;
+    let mut backoff_ms = RETRY_INITIAL_DELAY_MS;
+    let mut last_progress: u64 = 0;
+    let mut hasher = expected_sha256.as_ref().map(|_| Sha256::new());
+    let mut hasher_primed = false;
+    let mut preallocated = false;
+    let mut session_start: Option<(std::time::Instant, u64)> = None;
+    let mut speed_samples: std::collections::VecDeque<u64> = std::collections::VecDeque::with_capacity(SPEED_SAMPLE_WINDOW);
+
+    loop {
+        attempt += 1;
+        match attempt_download(
+            &client,
+            &final_url,
+            &headers,
+            &tmp_path,
+            &resolved_path,
+            &mut total_size,
+            &mut resume_offset,
+            &mut preallocated,
+            &mut cancel_rx,
+            &app_handle,
+            &download_id,
+            &mut last_progress,
+            expected_sha256.as_deref(),
+            hasher.as_mut(),
+            &mut hasher_primed,
+            &mut session_start,
+            &mut speed_samples,
+        ).await {
+            Ok(downloaded) => {
+                download_tracker::remove_download(&download_id);
+                log::info!("Download {} completed successfully ({} bytes)", download_id, downloaded);
+                return Ok(());
+            }
+            Err(DownloadAttemptError::Cancelled) => {
+                log::info!("Download {} cancelled", download_id);
+                download_tracker::remove_download(&download_id);
+                return Err("Download cancelled".to_string());
+            }
+            Err(DownloadAttemptError::Fatal(message)) => {
+                download_tracker::remove_download(&download_id);
+                return Err(message);
+            }
+            Err(e @ DownloadAttemptError::InsufficientSpace { .. }) | Err(e @ DownloadAttemptError::ChecksumMismatch { .. }) => {
+                download_tracker::remove_download(&download_id);
+                return Err(e.to_string());
+            }
+            Err(DownloadAttemptError::Transient(message)) => {
+                if retry_start.elapsed() >= std::time::Duration::from_secs(RETRY_MAX_ELAPSED_SECS) {
+                    download_tracker::remove_download(&download_id);
+                    return Err(format!("Download failed after {} attempts: {}", attempt, message));
+                }
+
+                log::warn!("Download {} attempt {} failed transiently: {}. Retrying in {}ms", download_id, attempt, message, backoff_ms);
+                let _ = app_handle.emit("download_retry", serde_json::json!({
+                    "download_id": download_id,
+                    "attempt": attempt,
+                    "delay_ms": backoff_ms,
+                    "error": message,
+                }));
+
+                // Wait out the backoff in short slices so a cancel_download call lands
+                // promptly instead of waiting for the full (possibly 30s) delay to elapse.
+                let backoff_deadline = std::time::Instant::now() + std::time::Duration::from_millis(backoff_ms);
+                loop {
+                    if matches!(cancel_rx.try_recv(), Ok(())) {
+                        log::info!("Download {} cancelled during retry backoff", download_id);
+                        download_tracker::remove_download(&download_id);
+                        return Err("Download cancelled".to_string());
+                    }
+                    let remaining = backoff_deadline.saturating_duration_since(std::time::Instant::now());
+                    if remaining.is_zero() {
+                        break;
+                    }
+                    tokio::time::sleep(remaining.min(std::time::Duration::from_millis(100))).await;
+                }
+                backoff_ms = (backoff_ms * 2).min(RETRY_MAX_DELAY_MS);
+            }
+        }
+    }
 }
 
 // Command to cancel a download
@@ -300,6 +789,86 @@ async fn cancel_download(download_id: String) -> Result<bool, String> {
     Ok(cancelled)
 }
 
+// Command to enqueue a batch of downloads (e.g. an entire folder) to run under a bounded
+// worker pool instead of all firing at once. Each job still goes through the same download
+// logic as `download_file`, so per-job cancellation and progress events keep working exactly
+// as before; this just gates how many run concurrently and reports aggregate progress for
+// the whole batch.
+#[tauri::command]
+async fn enqueue_downloads(jobs: Vec<download_tracker::DownloadJob>, app_handle: tauri::AppHandle) -> Result<(), String> {
+    let total = jobs.len();
+    log::info!("Enqueuing {} downloads", total);
+
+    let completed = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let failed = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let mut handles = Vec::with_capacity(total);
+    for job in jobs {
+        let app_handle = app_handle.clone();
+        let completed = completed.clone();
+        let failed = failed.clone();
+
+        handles.push(tokio::spawn(async move {
+            let download_id = job.download_id.clone();
+
+            // Register for cancellation right away, before we even have a concurrency slot,
+            // so cancel_download has something to signal while this job is still queued
+            // instead of only once it starts. The same receiver is handed to
+            // `download_file_with_cancel` below rather than letting it register a second,
+            // overwriting entry of its own - otherwise a cancel landing in the gap between
+            // acquiring the permit and that second registration would be silently dropped.
+            let (queue_cancel_tx, mut queue_cancel_rx) = tokio::sync::oneshot::channel::<()>();
+            download_tracker::add_download(download_id.clone(), queue_cancel_tx);
+
+            // Wait for a free slot before starting this job's HEAD/GET requests, unless
+            // it gets cancelled first while still waiting in the queue. The semaphore is
+            // fetched here, at acquire time, rather than captured once before the batch
+            // starts spawning, so a `set_max_concurrency` call made while this batch is
+            // still draining takes effect for jobs that haven't started waiting yet.
+            let permit = tokio::select! {
+                permit = download_tracker::semaphore().acquire_owned() => Some(permit.expect("download semaphore was closed")),
+                _ = &mut queue_cancel_rx => None,
+            };
+
+            if let Some(_permit) = permit {
+                if let Err(e) = download_file_with_cancel(&job.url, &job.save_path, job.auth_token.clone(), download_id.clone(), app_handle.clone(), job.expected_sha256.clone(), queue_cancel_rx).await {
+                    log::warn!("Queued download {} failed: {}", download_id, e);
+                    failed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            } else {
+                log::info!("Queued download {} cancelled before it started", download_id);
+                download_tracker::remove_download(&download_id);
+                failed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+
+            completed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let queue_progress = serde_json::json!({
+                "completed": completed.load(std::sync::atomic::Ordering::SeqCst),
+                "failed": failed.load(std::sync::atomic::Ordering::SeqCst),
+                "total": total,
+            });
+            let _ = app_handle.emit("queue_progress", queue_progress);
+        }));
+    }
+
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+    Ok(())
+}
+
+// Command to change how many queued downloads are allowed to run at once
+#[tauri::command]
+fn set_max_concurrency(max: usize) -> Result<(), String> {
+    if max == 0 {
+        return Err("max_concurrency must be at least 1".to_string());
+    }
+    log::info!("Setting max download concurrency to {}", max);
+    download_tracker::set_max_concurrency(max);
+    Ok(())
+}
+
 fn main() {
   // Set default log level if not already set
   if std::env::var("RUST_LOG").is_err() {
@@ -320,6 +889,8 @@ fn main() {
       log_error,
       download_file,
       cancel_download,
+      enqueue_downloads,
+      set_max_concurrency,
       open_file_in_folder
     ])
     .setup(|_app| {
@@ -351,4 +922,74 @@ fn main() {
     })
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Resume-after-restart: the sidecar reflects genuinely-downloaded bytes and should be
+    // trusted as-is when it doesn't exceed what's really on disk.
+    #[test]
+    fn resolve_resume_offset_trusts_sidecar_within_file_len() {
+        assert_eq!(resolve_resume_offset(Some(1000), Some(400), 2000), 400);
+    }
+
+    // Resume-after-restart: a sidecar claiming more bytes than are really on disk (the
+    // last write never made it past the OS before a hard kill) must be clamped, not
+    // trusted outright - otherwise the next attempt seeks past real EOF and zero-fills.
+    #[test]
+    fn resolve_resume_offset_clamps_sidecar_past_file_len() {
+        assert_eq!(resolve_resume_offset(Some(100), Some(900), 2000), 100);
+    }
+
+    // Resume-after-restart: a preallocated `.tmp` file (length already at total_size) with
+    // no sidecar at all can't be trusted as "fully downloaded" - it's indistinguishable
+    // from a process that died right after preallocation and before any real bytes landed.
+    #[test]
+    fn resolve_resume_offset_restarts_unconfirmed_preallocation() {
+        assert_eq!(resolve_resume_offset(Some(2000), None, 2000), 0);
+    }
+
+    // Without a sidecar, a partial (not fully preallocated) file's length is still a
+    // trustworthy resume point.
+    #[test]
+    fn resolve_resume_offset_falls_back_to_file_len_when_partial() {
+        assert_eq!(resolve_resume_offset(Some(500), None, 2000), 500);
+    }
+
+    // No `.tmp` file on disk at all means nothing to resume, regardless of any stale
+    // sidecar left behind by something else.
+    #[test]
+    fn resolve_resume_offset_is_zero_with_no_file() {
+        assert_eq!(resolve_resume_offset(None, Some(400), 2000), 0);
+    }
+
+    #[test]
+    fn verify_checksum_accepts_matching_digest_case_insensitively() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello world");
+        let expected = format!("{:X}", Sha256::new().chain_update(b"hello world").finalize());
+        assert!(verify_checksum(&expected, &mut hasher).is_ok());
+    }
+
+    #[test]
+    fn verify_checksum_rejects_mismatched_digest() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello world");
+        let wrong_expected = format!("{:x}", Sha256::new().chain_update(b"goodbye world").finalize());
+        let err = verify_checksum(&wrong_expected, &mut hasher).expect_err("digest should not match");
+        assert_ne!(err, wrong_expected);
+    }
+
+    #[test]
+    fn parse_content_range_start_reads_valid_header() {
+        assert_eq!(parse_content_range_start("bytes 1000-1999/5000"), Some(1000));
+    }
+
+    #[test]
+    fn parse_content_range_start_rejects_unparseable_header() {
+        assert_eq!(parse_content_range_start("bytes */5000"), None);
+        assert_eq!(parse_content_range_start("not-bytes-at-all"), None);
+    }
 }
\ No newline at end of file